@@ -1,43 +1,188 @@
-#![feature(plugin, collections,unicode)]
-#![plugin(docopt_macros)]
-
-extern crate collections;
-
 extern crate docopt;
-extern crate rustc_serialize;
-extern crate unicode;
+#[macro_use]
+extern crate serde_derive;
 
 use std::io::{BufReader, BufRead};
 use std::fs::File;
 use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::cmp;
+use docopt::Docopt;
 use suffix_tree::{SuffixTree, Cursor};
 
-docopt!(Args, "
-Usage: name-tagger [-w] [-i] DICT
+// Parsed at runtime rather than via the `docopt!` compiler-plugin macro:
+// that macro requires `#![feature(plugin)]`, which rustc dropped entirely
+// in 1.75, so nothing using it has built on a mainline compiler in years.
+// Modern `docopt` (1.x) decodes into a `serde::Deserialize` type rather
+// than a `rustc_serialize::Decodable` one, so `Args` derives that instead.
+const USAGE: &'static str = "
+Usage: name-tagger [-w] [-i] [-s] [--min-score=<s>] [--max-results=<n>] DICT
 
 Options:
     -w, --whole-name        Forbid matches of substrings of names
     -i, --insensitive       Permit matches to differ from name in case and punctuation
-");
+    -s, --smart-case        Permit a dictionary entry to match regardless of input case, but only
+                             when the entry itself is written entirely in lowercase
+    --min-score=<s>         Minimum score (0.0-1.0) for a fuzzy subsequence match to be reported [default: 0.0]
+    --max-results=<n>       Maximum number of fuzzy subsequence matches to report per line, 0 for unlimited [default: 0]
+";
 
-#[derive(Clone)]
-struct Candidate<'a, V: 'a> {
-    cursor: Cursor<'a, char, V>,
-    start: usize,
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_whole_name: bool,
+    flag_insensitive: bool,
+    flag_smart_case: bool,
+    flag_min_score: String,
+    flag_max_results: String,
+    arg_DICT: String,
 }
 
-
 fn is_punctuation(ch: char) -> bool {
     let punct = &"/|-.\\:,;+()";
     punct.contains(ch)
 }
 
-#[derive(Debug, Copy)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum TermType {
     Exact, Fuzzy, WholeWord, FuzzyWholeWord, WholeWordWithSymbols
 }
 
-type STree = SuffixTree<char, (TermType, String)>;
+// The dictionary only stores what a matcher cannot infer on its own: whether
+// the entry is eligible for smart-case folding (see PipelineMatcher) and the
+// label to report. Which TermType a match gets is decided by the matcher
+// that found it, since the same entry can be reached exactly or fuzzily.
+//
+// Labels (e.g. entity-type tags like "PERSON") repeat across thousands of
+// dictionary entries, so rather than storing an owned `String` per entry,
+// the dictionary stores a `Symbol` handle into an `Interner` and the text is
+// only resolved back out when a match is printed.
+type STree = SuffixTree<char, (bool, Symbol)>;
+
+/// A handle into an `Interner`. Cheap to copy and store per dictionary
+/// entry instead of an owned `String`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Symbol(u32);
+
+/// Deduplicates repeated dictionary labels: each distinct string is stored
+/// once and handed out as a `Symbol` that can be resolved back to the text
+/// later. Built once at dictionary load time.
+struct Interner {
+    strings: Vec<String>,
+    ids: HashMap<String, Symbol>,
+}
+
+impl Interner {
+    fn new() -> Interner {
+        Interner { strings: Vec::new(), ids: HashMap::new() }
+    }
+
+    fn intern(&mut self, s: &str) -> Symbol {
+        if let Some(&sym) = self.ids.get(s) {
+            return sym;
+        }
+        let sym = Symbol(self.strings.len() as u32);
+        self.strings.push(s.to_string());
+        self.ids.insert(s.to_string(), sym);
+        sym
+    }
+
+    fn resolve(&self, sym: Symbol) -> &str {
+        &self.strings[sym.0 as usize]
+    }
+}
+
+// Tuning for the scored fuzzy subsequence matcher (see find_fuzzy_matches below).
+// The first matched character always scores 1.0; every later matched character
+// is discounted by a factor that decays with the gap (in input characters)
+// since the previous matched character, floored at MIN_DISTANCE_PENALTY.
+const BASE_DISTANCE_PENALTY: f64 = 0.6;
+const ADDITIONAL_DISTANCE_PENALTY: f64 = 0.05;
+const MIN_DISTANCE_PENALTY: f64 = 0.2;
+
+/// A 64-bit summary of which characters a string contains: one bit per
+/// `a`-`z`/`0`-`9`, with everything else folded into the remaining bits.
+/// Used as a cheap, conservative prefilter: if a string's bag is not a
+/// subset of a window's bag, the string cannot possibly occur (as an exact
+/// or subsequence match) within that window.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct CharBag(u64);
+
+impl CharBag {
+    fn empty() -> CharBag { CharBag(0) }
+
+    fn of_char(ch: char) -> CharBag {
+        let lower = ch.to_lowercase().next().unwrap_or(ch);
+        let bit = if lower >= 'a' && lower <= 'z' {
+            lower as u32 - 'a' as u32
+        } else if lower >= '0' && lower <= '9' {
+            26 + (lower as u32 - '0' as u32)
+        } else {
+            36 + (lower as u32 % 28)
+        };
+        CharBag(1 << bit)
+    }
+
+    fn union(self, other: CharBag) -> CharBag { CharBag(self.0 | other.0) }
+
+    fn intersect(self, other: CharBag) -> CharBag { CharBag(self.0 & other.0) }
+
+    fn contains_all(self, available: CharBag) -> bool {
+        self.0 & available.0 == self.0
+    }
+}
+
+/// Index from a trie node to the `CharBag` of characters that *every* path
+/// from that node down to a terminal must still consume. This is an index
+/// parallel to the dictionary trie (rather than data stored directly on the
+/// nodes) so it can be thrown away and recomputed if the trie ever changes.
+type RequiredBags<V> = HashMap<*const SuffixTree<char, V>, CharBag>;
+
+/// The `CharBag` of a dictionary label that a fuzzy candidate genuinely
+/// needs present somewhere in the remaining input. Whitespace/punctuation
+/// separators are excluded: `expand_dict_separators` lets a candidate step
+/// past those for free, with no corresponding input character at all, so
+/// requiring them to appear in the input would reject matches that should
+/// be allowed to skip them (e.g. "JRR Tolkien" against "J. R. R. Tolkien").
+fn required_label_bag(label: &[char]) -> CharBag {
+    label.iter().fold(CharBag::empty(), |b, &ch| {
+        if ch.is_whitespace() || is_punctuation(ch) { b } else { b.union(CharBag::of_char(ch)) }
+    })
+}
+
+fn compute_required_bags<V>(node: &SuffixTree<char, V>, bags: &mut RequiredBags<V>) -> CharBag {
+    // A terminal can always complete a match right here, with zero
+    // additional characters -- even if it also has children (e.g. "tom"
+    // terminal with "tomato" continuing below it) -- so nothing is
+    // required going forward from it, regardless of what lies below.
+    let required = if node.is_terminal() {
+        CharBag::empty()
+    } else {
+        let mut required: Option<CharBag> = None;
+        for (label, child) in node.edges() {
+            let label_bag = required_label_bag(label);
+            // What's required to reach a terminal via this edge is the
+            // edge's own label plus whatever the child still requires
+            // beyond it -- not folded into the child's own entry, which
+            // must describe only what's required from the child onward.
+            let via_child = compute_required_bags(child, bags).union(label_bag);
+            required = Some(match required {
+                Some(r) => r.intersect(via_child),
+                None => via_child,
+            });
+        }
+        required.unwrap_or(CharBag::empty())
+    };
+    bags.insert(node as *const SuffixTree<char, V>, required);
+    required
+}
+
+fn input_suffix_bags(input: &[char]) -> Vec<CharBag> {
+    let mut bags = vec![CharBag::empty(); input.len() + 1];
+    for i in (0..input.len()).rev() {
+        bags[i] = bags[i + 1].union(CharBag::of_char(input[i]));
+    }
+    bags
+}
 
 //let norma = str.map(|ch| if is_punctuation(ch) { '.' } else { ch }).flat_map(|ch| ch.to_lowercase())
 
@@ -49,75 +194,99 @@ fn normalize<'a, Iter: Iterator<Item=char> + 'a>(str: Iter) -> Box<Iterator<Item
     )
 }
 
+/// Splits one line of a dictionary file into its `label\tterm` columns,
+/// or `None` for a line that doesn't have both. Pulled out of the loading
+/// loop so this has test coverage of its own: the loop used to call
+/// `splitn(1, ...)`, which can never produce a second column, so every
+/// dictionary ever loaded through it was silently empty.
+fn parse_dict_line(line: &str) -> Option<(&str, &str)> {
+    let parts: Vec<&str> = line.trim_right_matches('\n').splitn(2, '\t').collect();
+    if parts.len() == 2 {
+        Some((parts[0], parts[1]))
+    } else {
+        None
+    }
+}
+
 pub fn main() {
-    let args: Args = Args::docopt().decode().unwrap_or_else(|e| e.exit());
+    let args: Args = Docopt::new(USAGE).and_then(|d| d.deserialize()).unwrap_or_else(|e| e.exit());
     let name_only = args.flag_whole_name;
     let fuzzy = args.flag_insensitive;
+    let smart_case = args.flag_smart_case;
+    let min_score: f64 = args.flag_min_score.parse().unwrap_or(0.0);
+    let max_results: usize = args.flag_max_results.parse().unwrap_or(0);
 
     // read in dictionary
     let dict_path = Path::new(&args.arg_DICT);
     let dict_reader = BufReader::new(File::open(&dict_path).unwrap());
     let mut dict: STree = SuffixTree::new();
+    let mut interner = Interner::new();
     for i in dict_reader.lines() {
         let i = i.unwrap();
-        let parts: Vec<&str> = i.trim_right_matches('\n').splitn(1, '\t').collect();
-        match parts.len() {
-            2 => {
-                let t: Vec<char> = parts[1].chars().collect();
-                if name_only {
-                    dict.insert(Some(' ').into_iter()
-                                .chain(t.clone().into_iter())
-                                .chain(Some(' ').into_iter()),
-                                (TermType::WholeWord, parts[0].to_string()));
-
-                    if fuzzy {
-                        let normalized = normalize(t.into_iter());
-                        dict.insert(Some(' ').into_iter().chain(normalized).chain(Some(' ').into_iter()),
-                                    (TermType::FuzzyWholeWord, parts[0].to_string()));
-                    }
-                } else {
-                    dict.insert(t.clone().into_iter(), (TermType::Exact, parts[0].to_string()));
-                    if fuzzy {
-                        let normalized = normalize(t.into_iter());
-                        dict.insert(normalized, (TermType::Fuzzy, parts[0].to_string()));
-                    }
-                }
-            },
-            _ => {}
+        if let Some((raw_label, term)) = parse_dict_line(&i) {
+            let t: Vec<char> = term.chars().collect();
+            let is_lowercase = t.iter().all(|c| !c.is_uppercase());
+            let label = interner.intern(raw_label);
+            if name_only {
+                dict.insert(Some(' ').into_iter()
+                            .chain(t.into_iter())
+                            .chain(Some(' ').into_iter()),
+                            (is_lowercase, label));
+            } else {
+                dict.insert(t.into_iter(), (is_lowercase, label));
+            }
         }
     }
 
+    let required_bags: RequiredBags<(bool, Symbol)> = {
+        let mut bags = HashMap::new();
+        compute_required_bags(&dict, &mut bags);
+        bags
+    };
+
     let stdin = std::io::stdin();
     for line in stdin.lock().lines() {
         use std::iter::FromIterator;
         let line = line.unwrap();
         let line = line.trim_right_matches('\n');
-        let matches =
-            find_matches(&dict,
-                         Some(' ').into_iter()
-                         .chain(line.chars())
-                         .chain(Some(' ').into_iter())
-            );
-
-        for m in matches.into_iter() {
-            let &(ty, ref value) = m.node.value.as_ref().unwrap();
-            let seq: String = FromIterator::from_iter(m.seq.into_iter());
-            println!("{}\t{}\t{}\t{}\t{:?}\t{}",
-                     m.start - 1, m.end - 1, seq, true, ty, value);
+
+        let mut pipeline = PipelineMatcher::new(&dict, name_only, fuzzy, smart_case);
+        let padded: Vec<char> =
+            Some(' ').into_iter()
+            .chain(line.chars())
+            .chain(Some(' ').into_iter())
+            .collect();
+
+        for (offset, &ch) in padded.iter().enumerate() {
+            for m in pipeline.feed(offset, ch).into_iter() {
+                let &(_, symbol) = m.node.value.as_ref().unwrap();
+                let value = interner.resolve(symbol);
+                let seq: String = FromIterator::from_iter(m.seq.into_iter());
+                println!("{}\t{}\t{}\t{}\t{:?}\t{}",
+                         m.start - 1, m.end - 1, seq, m.term_type == TermType::Exact || m.term_type == TermType::WholeWord, m.term_type, value);
+            }
         }
 
-        let matches =
-            find_matches(&dict,
-                         Some(' ').into_iter()
-                         .chain(normalize(line.chars()))
-                         .chain(Some(' ').into_iter())
-            );
-
-        for m in matches.into_iter() {
-            let &(ty, ref value) = m.node.value.as_ref().unwrap();
-            let seq: String = FromIterator::from_iter(m.seq.into_iter());
-            println!("{}\t{}\t{}\t{}\t{:?}\t{}",
-                     m.start - 1, m.end - 1, seq, false, ty, value);
+        if fuzzy {
+            let normalized: Vec<char> =
+                Some(' ').into_iter()
+                .chain(normalize(line.chars()))
+                .chain(Some(' ').into_iter())
+                .collect();
+            let mut matches = find_fuzzy_matches(&dict, &normalized, &required_bags);
+            matches.retain(|m| m.score >= min_score);
+            matches.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+            if max_results > 0 && matches.len() > max_results {
+                matches.truncate(max_results);
+            }
+
+            for m in matches.into_iter() {
+                let &(_, symbol) = m.node.value.as_ref().unwrap();
+                let value = interner.resolve(symbol);
+                let seq: String = FromIterator::from_iter(m.seq.into_iter());
+                println!("{}\t{}\t{}\t{}\t{:?}\t{}\t{:.3}",
+                         m.start - 1, m.end - 1, seq, false, m.term_type, value, m.score);
+            }
         }
         println!("");
     }
@@ -128,195 +297,710 @@ struct Match<'a, V: 'a> {
     end: usize,
     seq: Vec<char>,
     node: &'a SuffixTree<char, V>,
+    score: f64,
+    term_type: TermType,
 }
 
-fn find_matches<'a, Iter: Iterator<Item=char>, V>
-    (dict: &'a SuffixTree<char, V>,
-     input: Iter) -> Vec<Match<'a, V>> {
-
-    let mut cands: Vec<Candidate<V>> = Vec::new();
-    let mut matches: Vec<Match<V>> = Vec::new();
-    for (offset, ch) in input.enumerate() {
-		cands.push(Candidate {cursor: Cursor::new(dict), start: offset, term_type_downgraded = false});
-
-		cands = cands.into_iter().flat_map(|cand: Candidate<'a, V>| {
-			match cand.cursor.clone().go(ch) {
-				Some(next) => vec!(Candidate {cursor: next, start: cand.start, term_type_downgraded = cand.term_type_downgraded}),
-				None => vec!(),
-//                None => if(skipSymbol) { vec!(Candidate {cursor: next, start: cand.start, term_type_downgraded = true}) }
-			}.into_iter()
-		}).collect();
-
-		for cand in cands.iter() {
-			if cand.cursor.get().is_terminal() {
-				// we have a hit
-				matches.push(Match{
-					start: cand.start,
-					end: 1 + offset,
-					seq: cand.cursor.path.clone(),
-					node: cand.cursor.get(),
-				});
-			}
-		}
-    }
-    matches
+/// A streaming matcher: fed one input character at a time, reporting
+/// whatever matches complete at that position.
+trait Matcher<'a> {
+    fn feed(&mut self, offset: usize, ch: char) -> Vec<Match<'a, (bool, Symbol)>>;
 }
 
+/// How permissively a `PipelineCandidate` had to deviate from an exact
+/// character-for-character match to survive. Stages only ever increase as a
+/// candidate is fed more input, and the highest stage reached (together with
+/// `-w`) determines the `TermType` a completed match is reported under.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage {
+    Exact,
+    Fuzzy,
+    Symbols,
+}
 
-trait Matcher {
-    fn feed(offset:usize, ch:char) -> Vec<Match>;
+#[derive(Clone)]
+struct PipelineCandidate<'a> {
+    cursor: Cursor<'a, char, (bool, Symbol)>,
+    start: usize,
+    stage: Stage,
+    // How many real trie steps this candidate has actually taken, as
+    // opposed to merely being seeded at the root. Mirrors
+    // `FuzzyCandidate::matched`: a candidate that has taken zero steps is
+    // still sitting at the root `feed()` seeds fresh every offset, so it
+    // must never be kept alive unadvanced -- otherwise it survives any
+    // transform that doesn't move the cursor (see `SpaceSkipCandidate`,
+    // `SymbolSkipCandidate`) and later reports a match with a stale `start`
+    // from whatever offset it happened to be seeded at.
+    matched: usize,
 }
 
-struct ExactMatcher {
-    tree: &SuffixTree,
-	cands: Vec<Candidate>,
+/// A candidate transformer: one independent rule for advancing a
+/// `PipelineCandidate` past a character that an exact trie lookup (always
+/// tried first, directly via `Cursor::go`) rejected. `PipelineMatcher`
+/// holds an ordered, independently configurable list of these; the first
+/// one that accepts `ch` wins.
+trait CandidateTransform {
+    /// The `Stage` a candidate takes on once it has needed this transform
+    /// to proceed.
+    fn stage(&self) -> Stage;
+
+    /// Try to consume `ch` starting from `cursor`. Returns the cursor
+    /// afterwards, or `None` if this transform doesn't apply to `ch`.
+    fn advance<'a>(&self, cursor: &Cursor<'a, char, (bool, Symbol)>, ch: char) -> Option<Cursor<'a, char, (bool, Symbol)>>;
+
+    /// Like `advance`, but for a dictionary-side requirement that has no
+    /// corresponding input character at all -- e.g. the space in a
+    /// whole-word entry "St Louis" when the input has none -- so it
+    /// advances past it without consuming `ch`. `None` by default, since
+    /// most transforms only ever consume extra input.
+    fn advance_dict_only<'a>(&self, _cursor: &Cursor<'a, char, (bool, Symbol)>) -> Option<Cursor<'a, char, (bool, Symbol)>> {
+        None
+    }
 }
-impl Matcher for ExactMatcher {
-    fn feed(offset:usize, ch:char) -> Vec<Match> {
-        let mut matches = Vec::new();
-		cands.push(Candidate {cursor: Cursor::new(dict), start: offset});
-
-		cands = cands.into_iter().flat_map(|cand: Candidate<'a, V>| {
-			match cand.cursor.clone().go(ch) {
-				Some(next) => vec!(Candidate {cursor: next, start: cand.start}),
-				None => vec!(),
-				//                None => if(skipSymbol) { vec!(Candidate {cursor: next, start: cand.start, term_type_downgraded = true}) }
-			}.into_iter()
-		}).collect();
-
-		for cand in cands.iter() {
-			if cand.cursor.get().is_terminal() {
-				// we have a hit
-				matches.push(Match{
-					start: cand.start,
-					end: 1 + offset,
-					seq: cand.cursor.path.clone(),
-					node: cand.cursor.get(),
-				});
-			}
-		}
+
+/// Retries the trie step comparing case-insensitively, so e.g. "Tolkien"
+/// can reach a dictionary entry written as "tolkien" *and* "tolkien" can
+/// reach one written as "Tolkien".
+struct LowerCaseCandidate;
+
+impl CandidateTransform for LowerCaseCandidate {
+    fn stage(&self) -> Stage { Stage::Fuzzy }
+
+    fn advance<'a>(&self, cursor: &Cursor<'a, char, (bool, Symbol)>, ch: char) -> Option<Cursor<'a, char, (bool, Symbol)>> {
+        let folded = ch.to_lowercase().next().unwrap_or(ch);
+        cursor.clone().go_matching(move |&edge_ch| edge_ch.to_lowercase().next().unwrap_or(edge_ch) == folded)
     }
 }
 
-struct Candidate<'a, V: 'a> {
-cursor: Cursor<'a, char, V>,
-start: usize,
+/// Absorbs a run of whitespace without advancing the trie, so it collapses
+/// to the single space a whole-word entry is padded with -- and, the other
+/// way round, advances past a dictionary-side space that has no input
+/// character standing in for it at all.
+struct SpaceSkipCandidate;
+
+impl CandidateTransform for SpaceSkipCandidate {
+    fn stage(&self) -> Stage { Stage::Fuzzy }
+
+    fn advance<'a>(&self, cursor: &Cursor<'a, char, (bool, Symbol)>, ch: char) -> Option<Cursor<'a, char, (bool, Symbol)>> {
+        if ch.is_whitespace() { Some(cursor.clone()) } else { None }
+    }
+
+    fn advance_dict_only<'a>(&self, cursor: &Cursor<'a, char, (bool, Symbol)>) -> Option<Cursor<'a, char, (bool, Symbol)>> {
+        cursor.clone().go_matching(|&c: &char| c.is_whitespace())
+    }
 }
 
-fn consume_iterator<Iter: Iterator<Item=char>>(iter: Iter, mut localcursor: Cursor) -> Option<Cursor> {
-	for lch in iter {
-		match localcursor.go(lch) {
-			Some(cur) => localcursor = cur,
-			None => return None
-		}
-	}
-	Some(cur)
+/// Skips a punctuation character outright (e.g. the '.' in "St.-Louis"),
+/// so a symbol-separated variant can reach an entry that has none -- and,
+/// the other way round, advances past a dictionary-side punctuation
+/// character that has no input character standing in for it at all.
+struct SymbolSkipCandidate;
+
+impl CandidateTransform for SymbolSkipCandidate {
+    fn stage(&self) -> Stage { Stage::Symbols }
+
+    fn advance<'a>(&self, cursor: &Cursor<'a, char, (bool, Symbol)>, ch: char) -> Option<Cursor<'a, char, (bool, Symbol)>> {
+        if is_punctuation(ch) { Some(cursor.clone()) } else { None }
+    }
+
+    fn advance_dict_only<'a>(&self, cursor: &Cursor<'a, char, (bool, Symbol)>) -> Option<Cursor<'a, char, (bool, Symbol)>> {
+        cursor.clone().go_matching(|&c: &char| is_punctuation(c))
+    }
 }
 
-fn consumeLowerCase(ch: char, mut localcursor: Cursor) -> Option<Cursor> {
-    consume_iterator(ch.to_lowercase, localcursor)
+/// The composed matcher backing `-i`/`--insensitive` (and `-w`/`-s`, which
+/// shape its output rather than gate it): every candidate always tries an
+/// `Exact` character-for-character lookup first, and if that fails, falls
+/// back in order through whichever `CandidateTransform`s are active -- so
+/// e.g. "St.-Louis" can reach a `WholeWord` entry for "St Louis" and be
+/// reported as `WholeWordWithSymbols`. Before each input character, a
+/// candidate is also expanded through any dictionary-side separators a
+/// transform reports via `advance_dict_only`, so a required space or
+/// punctuation character with no input counterpart at all doesn't block
+/// the match either. Each candidate remembers only the most permissive
+/// fallback it has needed so far; later exact matches don't "upgrade" it
+/// back towards `Exact`.
+///
+/// Without `-i` or `-s`, `transforms` is empty and only the `Exact` lookup
+/// runs, equivalent to the original case/punctuation-sensitive matcher.
+/// `-s` alone enables case folding (`LowerCaseCandidate`) without the
+/// separator-skipping transforms, which are only ever part of `-i`.
+struct PipelineMatcher<'a> {
+    dict: &'a SuffixTree<char, (bool, Symbol)>,
+    whole_word: bool,
+    smart_case: bool,
+    transforms: Vec<Box<CandidateTransform>>,
+    cands: Vec<PipelineCandidate<'a>>,
+    // A completed candidate stays alive (it may still extend into a longer
+    // entry sharing its prefix, e.g. "tom" -> "tomato"), so without this it
+    // would be re-reported as a fresh match on every later offset it
+    // survives unadvanced. Mirrors `find_fuzzy_matches`'s `reported` set.
+    reported: HashSet<(usize, *const SuffixTree<char, (bool, Symbol)>, usize)>,
 }
 
-fn consumeSkipSpace(ch:char, mut localcursor:Cursor) -> Option<Cursor> {
-    if(ch.is_whitespace() || ch.is_newline()) {
-		if(localcursor.head == ' ') {
-			return localcursor
-		}
-	}
+impl<'a> PipelineMatcher<'a> {
+    fn new(dict: &'a SuffixTree<char, (bool, Symbol)>, whole_word: bool, fuzzy: bool, smart_case: bool) -> PipelineMatcher<'a> {
+        // LowerCaseCandidate is the only transform that folds case, so it
+        // has to run whenever *either* `-i` or `-s` is given -- `-s` is its
+        // own independent flag (see USAGE) and must work without `-i`.
+        // Separator-skipping, by contrast, is only ever part of `-i`.
+        let mut transforms: Vec<Box<CandidateTransform>> = Vec::new();
+        if fuzzy || smart_case {
+            transforms.push(Box::new(LowerCaseCandidate));
+        }
+        if fuzzy {
+            transforms.push(Box::new(SpaceSkipCandidate));
+            transforms.push(Box::new(SymbolSkipCandidate));
+        }
+        PipelineMatcher {
+            dict: dict,
+            whole_word: whole_word,
+            smart_case: smart_case,
+            transforms: transforms,
+            cands: Vec::new(),
+            reported: HashSet::new(),
+        }
+    }
+
+    fn term_type(&self, stage: Stage) -> TermType {
+        match (self.whole_word, stage) {
+            (false, Stage::Exact) => TermType::Exact,
+            (false, _) => TermType::Fuzzy,
+            (true, Stage::Exact) => TermType::WholeWord,
+            (true, Stage::Fuzzy) => TermType::FuzzyWholeWord,
+            (true, Stage::Symbols) => TermType::WholeWordWithSymbols,
+        }
+    }
+
+    /// Expand a candidate through any chain of dictionary-side separators
+    /// (space/punctuation trie edges with no corresponding input character)
+    /// before it tries to match the next real input character, so e.g. the
+    /// required space in a "St Louis" entry doesn't block "St.-Louis".
+    fn expand_dict_only(&self, cand: &PipelineCandidate<'a>) -> Vec<PipelineCandidate<'a>> {
+        let mut all = vec![cand.clone()];
+        // A candidate that hasn't matched any real input yet is either the
+        // root `feed()` just seeded this offset, or one that was itself
+        // only reached via a prior no-op skip -- either way, letting it
+        // walk a dictionary-side separator edge for free here would let it
+        // reach a terminal, or pick up fuzzy/whole-word status, from pure
+        // separator-skipping with zero real input consumed. That's exactly
+        // the `moved || cand.matched > 0` rule the real per-character
+        // fallback in `feed` enforces a few lines below; apply it here too.
+        if cand.matched == 0 {
+            return all;
+        }
+        let mut frontier = vec![cand.clone()];
+        while let Some(c) = frontier.pop() {
+            for transform in self.transforms.iter() {
+                if let Some(cursor) = transform.advance_dict_only(&c.cursor) {
+                    // This hop consumes no real input, so it doesn't add to
+                    // `matched` -- it only ever runs for a candidate that
+                    // already has some (checked above), and stays that way.
+                    let next = PipelineCandidate { cursor: cursor, start: c.start, stage: cmp::max(c.stage, transform.stage()), matched: c.matched };
+                    all.push(next.clone());
+                    frontier.push(next);
+                }
+            }
+        }
+        all
+    }
 }
 
-fn consumeSkip(ch:char) -> Option<Cursor> {
+impl<'a> Matcher<'a> for PipelineMatcher<'a> {
+    fn feed(&mut self, offset: usize, ch: char) -> Vec<Match<'a, (bool, Symbol)>> {
+        self.cands.push(PipelineCandidate {cursor: Cursor::new(self.dict), start: offset, stage: Stage::Exact, matched: 0});
+
+        let pending: Vec<PipelineCandidate<'a>> = self.cands.drain(..).collect();
+        let expanded: Vec<PipelineCandidate<'a>> = pending.iter()
+            .flat_map(|cand| self.expand_dict_only(cand))
+            .collect();
+
+        let mut next_cands = Vec::new();
+        for cand in expanded {
+            if let Some(cursor) = cand.cursor.clone().go(ch) {
+                next_cands.push(PipelineCandidate {cursor: cursor, start: cand.start, stage: cand.stage, matched: cand.matched + 1});
+                continue;
+            }
+
+            let advanced = self.transforms.iter()
+                .filter_map(|transform| transform.advance(&cand.cursor, ch).map(|cursor| (cursor, transform.stage())))
+                .next();
+            if let Some((cursor, stage)) = advanced {
+                // SpaceSkipCandidate/SymbolSkipCandidate absorb an input
+                // separator without moving the cursor at all. That's only
+                // meaningful progress for a candidate that has already
+                // matched something for real -- a still-at-root candidate
+                // (matched == 0) left unadvanced like this would just carry
+                // its stale `start` forward under a fresh identity that
+                // later wins the dedup below, since main() pads every line
+                // with a leading/trailing space that this would absorb.
+                let moved = cursor.identity() != cand.cursor.identity();
+                if moved || cand.matched > 0 {
+                    let matched = cand.matched + if moved { 1 } else { 0 };
+                    next_cands.push(PipelineCandidate {cursor: cursor, start: cand.start, stage: cmp::max(cand.stage, stage), matched: matched});
+                }
+            }
+        }
+        self.cands = next_cands;
 
+        let mut matches = Vec::new();
+        for cand in self.cands.iter() {
+            if cand.matched > 0 && cand.cursor.is_terminal() {
+                let &(smart_case_ok, _) = cand.cursor.get().value.as_ref().unwrap();
+                if cand.stage != Stage::Exact && self.smart_case && !smart_case_ok {
+                    continue;
+                }
+                let (node_ptr, remaining) = cand.cursor.identity();
+                if !self.reported.insert((cand.start, node_ptr, remaining)) {
+                    continue;
+                }
+                matches.push(Match{
+                    start: cand.start,
+                    end: 1 + offset,
+                    seq: cand.cursor.path.clone(),
+                    node: cand.cursor.get(),
+                    score: 1.0,
+                    term_type: self.term_type(cand.stage),
+                });
+            }
+        }
+        matches
+    }
 }
 
-trait MyCandidate {
-    fn consume(ch:char) -> Vec<MyCandidate>
+#[derive(Clone)]
+struct FuzzyCandidate<'a, V: 'a> {
+    cursor: Cursor<'a, char, V>,
+    start: usize,
+    matched: usize,
+    last_match_offset: usize,
+    score: f64,
 }
-impl ExactCandidate {
-    fn consume(ch:char) -> Vec<MyCandidate> {
-        match cand.cursor.clone().go(ch) {
-            Some(next) => vec!(ExactCandidate{cursor:next, start:cand.start}})
-            None => {
-			    vec!(
-                    SpaceSkipCandidate{cursor:cand.cursor.clone(), start:cand.start}.consume(ch),
-                    LowerCaseCandidate{cursor:cand.cursor.clone(), start:cand.start}.consume(ch),
-                );
-			}
+
+// Expand a candidate through any chain of dictionary-side separators
+// (space/punctuation trie edges with no corresponding input character),
+// so e.g. "J. R. R. Tolkien" can be reached by "JRR Tolkien" in the input
+// even though nothing in the input stands in for the dots and spaces.
+fn expand_dict_separators<'a, V: Clone>(cand: FuzzyCandidate<'a, V>) -> Vec<FuzzyCandidate<'a, V>> {
+    let mut all = vec![cand.clone()];
+    let mut frontier = vec![cand];
+    while let Some(c) = frontier.pop() {
+        if let Some(next) = c.cursor.clone().go_matching(|&e: &char| e.is_whitespace() || is_punctuation(e)) {
+            let advanced = FuzzyCandidate { cursor: next, ..c.clone() };
+            all.push(advanced.clone());
+            frontier.push(advanced);
         }
     }
+    all
 }
-impl LowerCaseCandidate {
-    fn consume(ch:char) -> Vec<MyCandidate> {
-		match consumeLowerCase(ch, cand.cursor.clone()) {
-			Some(next) => vec!(LowerCaseCandidate{cursor:next, start:cand.start}})
-			None => {
-				vec!(
-				SpaceSkipLowerCaseCandidate{cursor:cand.cursor.clone(), start:cand.start}.consume(ch),
-				);
-			}
-		}
 
+/// Matches dictionary entries against `input` as an ordered subsequence: a
+/// candidate may "skip" any number of input characters without advancing in
+/// the trie, and may likewise advance past a dictionary-side separator or
+/// case-fold that has no input character to match it at all, so e.g.
+/// "JRR Tolkien" matches a dictionary entry for "J. R. R. Tolkien". Each
+/// emitted `Match` carries a `score` in (0.0, 1.0],
+/// the geometric mean of a per-character penalty that decays with the gap
+/// since the previous matched character (see the DISTANCE_PENALTY constants),
+/// with the gap penalty reset whenever the matched character begins a word.
+///
+/// Candidates are memoized on (trie node, input offset), keeping only the
+/// best-scoring candidate for a given pair, so repetitive input can't cause
+/// the candidate set to grow exponentially.
+fn find_fuzzy_matches<'a, V: Clone>
+    (dict: &'a SuffixTree<char, V>,
+     input: &[char],
+     required_bags: &RequiredBags<V>) -> Vec<Match<'a, V>> {
+
+    let suffix_bags = input_suffix_bags(input);
+    let mut cands: Vec<FuzzyCandidate<V>> = Vec::new();
+    let mut matches: Vec<Match<V>> = Vec::new();
+    // A completed candidate stays in `cands` (it may still extend into a
+    // longer entry sharing its prefix, e.g. "tom" -> "tomato"), so the same
+    // (start, trie position) would otherwise be reported again every later
+    // offset it survives unadvanced. Remember what's already been reported
+    // so each completion is only emitted once.
+    let mut reported: HashSet<(usize, *const SuffixTree<char, V>, usize)> = HashSet::new();
+
+    for (offset, &ch) in input.iter().enumerate() {
+        cands.push(FuzzyCandidate {
+            cursor: Cursor::new(dict),
+            start: offset,
+            matched: 0,
+            last_match_offset: offset,
+            score: 1.0,
+        });
+
+        let is_word_start = offset == 0 || is_punctuation(input[offset - 1]) || input[offset - 1].is_whitespace();
+
+        let window = suffix_bags[offset + 1];
+        // `required_bags` is keyed by node, but a cursor mid-edge (the
+        // common case once chunk0-5's trie compaction collapsed long
+        // unbranched runs into single edges) hasn't reached that node yet --
+        // `Cursor::get()` resolves to the edge's far end, not the cursor's
+        // actual position, so looking up the far node's bag alone ignores
+        // everything still required on the unconsumed remainder of the
+        // edge. Union that remainder back in so the prefilter reflects what
+        // a mid-edge cursor genuinely still needs.
+        let can_complete = |cursor: &Cursor<'a, char, V>| {
+            let node = cursor.get();
+            match required_bags.get(&(node as *const SuffixTree<char, V>)) {
+                Some(&required) => {
+                    required.union(required_label_bag(cursor.remaining_label())).contains_all(window)
+                }
+                None => true,
+            }
+        };
+
+        // Keyed on the cursor's (edge target, chars remaining on that edge)
+        // identity rather than a bare node pointer, so two candidates that
+        // are partway through the same compressed edge by different amounts
+        // aren't mistaken for duplicates of each other.
+        let mut seen: HashMap<(*const SuffixTree<char, V>, usize, usize), usize> = HashMap::new();
+        let mut next_cands: Vec<FuzzyCandidate<V>> = Vec::new();
+
+        let expanded: Vec<FuzzyCandidate<V>> = cands.into_iter().flat_map(expand_dict_separators).collect();
+
+        for cand in expanded {
+            // Try to advance the cursor by matching `ch` against the trie,
+            // exactly or (this matcher is only ever run under `-i`) case-
+            // insensitively, so e.g. "Tolkien" can be reached by the
+            // lowercase-normalized input fed to this function.
+            let stepped = cand.cursor.clone().go(ch)
+                .or_else(|| cand.cursor.clone().go_matching(|&e: &char| e.to_lowercase().next().unwrap_or(e) == ch));
+            if let Some(next) = stepped {
+                if can_complete(&next) {
+                    let gap = offset - cand.last_match_offset;
+                    let penalty = if cand.matched == 0 || gap <= 1 {
+                        // No characters were skipped to get here -- an
+                        // exact, contiguous step is never penalized.
+                        1.0
+                    } else if is_word_start {
+                        BASE_DISTANCE_PENALTY
+                    } else {
+                        (BASE_DISTANCE_PENALTY - (gap as f64 - 1.0) * ADDITIONAL_DISTANCE_PENALTY)
+                            .max(MIN_DISTANCE_PENALTY)
+                    };
+                    let advanced = FuzzyCandidate {
+                        cursor: next,
+                        start: cand.start,
+                        matched: cand.matched + 1,
+                        last_match_offset: offset,
+                        score: cand.score * penalty,
+                    };
+                    let (node_ptr, remaining) = advanced.cursor.identity();
+                    let key = (node_ptr, remaining, offset + 1);
+                    match seen.get(&key) {
+                        Some(&i) if next_cands[i].score >= advanced.score => {},
+                        Some(&i) => next_cands[i] = advanced,
+                        None => { seen.insert(key, next_cands.len()); next_cands.push(advanced); },
+                    }
+                }
+            }
+
+            // Also keep the candidate alive unadvanced, so it can match `ch`
+            // (or a later character) at a larger gap -- unless the prefilter
+            // shows it can never complete against what's left of the input.
+            //
+            // An un-started candidate (matched == 0) is never worth keeping
+            // alive this way: it's still sitting at the trie root, a fresh
+            // one is seeded at every offset already, and letting an old one
+            // survive would just leave a stale `start` sitting at the same
+            // root position as this offset's own candidate -- where it can
+            // silently win the `seen` dedup tie below and get reported as
+            // if matching began back at the old offset instead of here.
+            if cand.matched > 0 && can_complete(&cand.cursor) {
+                let (node_ptr, remaining) = cand.cursor.identity();
+                let key = (node_ptr, remaining, offset + 1);
+                match seen.get(&key) {
+                    Some(&i) if next_cands[i].score >= cand.score => {},
+                    Some(&i) => next_cands[i] = cand,
+                    None => { seen.insert(key, next_cands.len()); next_cands.push(cand); },
+                }
+            }
+        }
+        cands = next_cands;
+
+        for cand in cands.iter() {
+            if cand.matched > 0 && cand.cursor.is_terminal() {
+                let (node_ptr, remaining) = cand.cursor.identity();
+                if reported.insert((cand.start, node_ptr, remaining)) {
+                    matches.push(Match {
+                        start: cand.start,
+                        end: 1 + offset,
+                        seq: cand.cursor.path.clone(),
+                        node: cand.cursor.get(),
+                        score: cand.score.powf(1.0 / cand.matched as f64),
+                        term_type: TermType::Fuzzy,
+                    });
+                }
+            }
+        }
     }
+    matches
 }
 
-impl SpaceSkipCandidate {
-    fn consume(ch:char) -> Vec<MyCandidate> {
-        let localcursor = cand.cursor.clone();
-        if(cand.cursor.head == ' ') {
-			while(localcursor.go(' ').is_some()){}
-		}
-		match localcursor.go(ch) {
-			Some(next) => vec!(SpaceSkipCandidate{cursor:localcursor, start:cand.start})
-			None => vec!(SpaceSymbolSkipCandidate{cursor:localcursor, start:cand.start})
-		}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn bags_for<V>(dict: &SuffixTree<char, V>) -> RequiredBags<V> {
+        let mut bags = HashMap::new();
+        compute_required_bags(dict, &mut bags);
+        bags
+    }
+
+    // Regression test for a bug where the dictionary loader's `splitn(1, ...)`
+    // could never produce a second column, so every line was silently
+    // dropped and every dictionary ever loaded through it was empty.
+    #[test]
+    fn parse_dict_line_splits_label_and_term() {
+        assert_eq!(parse_dict_line("PERSON\tJ. R. R. Tolkien"), Some(("PERSON", "J. R. R. Tolkien")));
+        assert_eq!(parse_dict_line("PERSON\tAda\tLovelace"), Some(("PERSON", "Ada\tLovelace")));
+        assert_eq!(parse_dict_line("PERSON\tAda Lovelace\n"), Some(("PERSON", "Ada Lovelace")));
+        assert_eq!(parse_dict_line("no tab here"), None);
+        assert_eq!(parse_dict_line(""), None);
     }
-}
 
+    // Regression test for `Edge::split`, the one genuinely new and risky
+    // operation in the Patricia-trie rewrite: inserting "tobacco" after
+    // "tomato" diverges partway along the single compacted "tomato" edge
+    // ("to" shared, "mato" vs "bacco" differ), so the edge must be split
+    // into a branch node with the old continuation and the new one as
+    // siblings below it -- rather than, say, losing the original entry or
+    // merging the two together.
+    #[test]
+    fn suffix_tree_split_handles_keys_diverging_mid_edge() {
+        let mut dict: SuffixTree<char, &'static str> = SuffixTree::new();
+        dict.insert("tomato".chars(), "tomato");
+        dict.insert("tobacco".chars(), "tobacco");
 
-impl SpaceSkipLowerCaseCandidate {
-	fn consume(ch:char) -> Vec<MyCandidate> {
-		let mut localcursor = cand.cursor.clone();
-		if(cand.cursor.head == ' ') {
-			while(localcursor.go(' ').is_some()){}
-		}
-		for(lch in ch.to_lowercase()) {
-			match localcursor.go(lch) {
-				Some(cur) => localcursor = cur,
-				None => return vec!()
-			}
-		}
-		vec!(SpaceSkipLowerCaseCandidate{cursor:localcursor, start:cand.start})
-	}
-}
+        let go_all = |key: &str| -> Option<Cursor<char, &'static str>> {
+            key.chars().fold(Some(Cursor::new(&dict)), |cursor, ch| cursor.and_then(|c| c.go(ch)))
+        };
 
-impl SpaceSymbolSkipLowerCaseCandidate {
-	fn consume(ch:char) -> Vec<MyCandidate> {
-		let localcursor = cand.cursor.clone();
-		if(cand.cursor.head.is_space() || cand.cursor.head.is_symbol()) {
-			while(localcursor.go(' ').is_some() || localcursor.go('.').is_some()){}
-		}
-		match ch.to_lowercase().map(|lch| localcursor.go(lch)).last()) { // brrrr
-			Some(next) => vec!(SpaceSymbolSkipLowerCaseCandidate{cursor:localcursor, start:cand.start})
-			None => vec!()
-		}
-
-	}
-}
+        let tomato = go_all("tomato").expect("\"tomato\" should still be reachable after its edge is split");
+        assert!(tomato.is_terminal());
+        assert_eq!(tomato.get().value, Some("tomato"));
+
+        let tobacco = go_all("tobacco").expect("\"tobacco\" should be reachable via the new edge the split introduces");
+        assert!(tobacco.is_terminal());
+        assert_eq!(tobacco.get().value, Some("tobacco"));
+
+        // The shared prefix is a branch node, not an entry of its own.
+        let to = go_all("to").expect("the shared \"to\" prefix should still be reachable");
+        assert!(!to.is_terminal());
+    }
+
+    // Regression test for a bug where a terminal node's required-characters
+    // bag was folded together with the characters on the edge leading into
+    // it, so a plain short dictionary entry (no shared prefixes at all)
+    // could never satisfy its own (bogus) requirement and never matched.
+    #[test]
+    fn fuzzy_match_finds_short_entry_with_no_shared_prefixes() {
+        let mut dict: SuffixTree<char, ()> = SuffixTree::new();
+        dict.insert("tom".chars(), ());
+        let bags = bags_for(&dict);
+
+        let input: Vec<char> = " tom ".chars().collect();
+        let matches = find_fuzzy_matches(&dict, &input, &bags);
+
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].start, 1);
+        assert_eq!(matches[0].end, 4);
+    }
+
+    // A terminal that also has children below it (a shorter entry that is a
+    // prefix of a longer one) must still be matchable on its own.
+    #[test]
+    fn fuzzy_match_finds_terminal_prefix_of_a_longer_entry() {
+        let mut dict: SuffixTree<char, ()> = SuffixTree::new();
+        dict.insert("tom".chars(), ());
+        dict.insert("tomato".chars(), ());
+        let bags = bags_for(&dict);
+
+        let input: Vec<char> = " tom ".chars().collect();
+        let matches = find_fuzzy_matches(&dict, &input, &bags);
+
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].start, 1);
+        assert_eq!(matches[0].end, 4);
+    }
+
+    // Regression test for a bug where a completed candidate stayed in the
+    // candidate set and was re-reported as a new match on every later
+    // offset, and for a bug where every non-first matched character was
+    // penalized even when contiguous with no skipped characters.
+    #[test]
+    fn fuzzy_match_exact_contiguous_match_is_reported_once_near_full_score() {
+        let mut dict: SuffixTree<char, ()> = SuffixTree::new();
+        dict.insert("tolkien".chars(), ());
+        let bags = bags_for(&dict);
+
+        let input: Vec<char> = " tolkien is great ".chars().collect();
+        let matches = find_fuzzy_matches(&dict, &input, &bags);
+
+        assert_eq!(matches.len(), 1);
+        assert!(matches[0].score > 0.99, "score was {}", matches[0].score);
+    }
+
+    // Regression test for a bug where this function could never reach a
+    // capitalized or punctuation-laden dictionary entry, since only extra
+    // *input* characters were skippable and `Cursor::go` otherwise requires
+    // byte-for-byte equality.
+    #[test]
+    fn fuzzy_match_folds_case_and_skips_dictionary_punctuation() {
+        let mut dict: SuffixTree<char, ()> = SuffixTree::new();
+        dict.insert("J. R. R. Tolkien".chars(), ());
+        let bags = bags_for(&dict);
+
+        let input: Vec<char> = normalize(" JRR Tolkien ".chars()).collect();
+        let matches = find_fuzzy_matches(&dict, &input, &bags);
+
+        assert_eq!(matches.len(), 1, "expected one match, got {}", matches.len());
+    }
+
+    // Regression test for a direction bug where LowerCaseCandidate only
+    // folded an uppercase input character down, never the reverse, so
+    // typing a name in lowercase against a properly-capitalized entry
+    // never matched under `-i`.
+    #[test]
+    fn pipeline_matches_lowercase_input_against_capitalized_entry() {
+        let mut dict: SuffixTree<char, (bool, Symbol)> = SuffixTree::new();
+        dict.insert("Apple".chars(), (false, Symbol(0)));
+
+        let mut pipeline = PipelineMatcher::new(&dict, false, true, false);
+        let found = "apple".chars().enumerate()
+            .any(|(offset, ch)| !pipeline.feed(offset, ch).is_empty());
+
+        assert!(found, "\"apple\" should match dictionary entry \"Apple\" under -i");
+    }
+
+    // Regression test for a bug where `-s` without `-i` was a complete
+    // no-op: `PipelineMatcher::new` only ever added `LowerCaseCandidate`
+    // (the sole case-folding transform) when `fuzzy` was set, so `-s`
+    // alone never got a chance to fold case at all.
+    #[test]
+    fn pipeline_smart_case_alone_folds_case_for_lowercase_entries_only() {
+        let mut dict: SuffixTree<char, (bool, Symbol)> = SuffixTree::new();
+        dict.insert("tolkien".chars(), (true, Symbol(0)));
+        dict.insert("Gandalf".chars(), (false, Symbol(1)));
+
+        let mut pipeline = PipelineMatcher::new(&dict, false, false, true);
+        let found_lowercase_entry = "Tolkien".chars().enumerate()
+            .any(|(offset, ch)| !pipeline.feed(offset, ch).is_empty());
+        assert!(found_lowercase_entry, "\"Tolkien\" should match lowercase entry \"tolkien\" under -s alone");
+
+        let mut pipeline = PipelineMatcher::new(&dict, false, false, true);
+        let found_mixed_case_entry = "gandalf".chars().enumerate()
+            .any(|(offset, ch)| !pipeline.feed(offset, ch).is_empty());
+        assert!(!found_mixed_case_entry, "\"gandalf\" should not match mixed-case entry \"Gandalf\" under -s");
+    }
 
+    // Regression test for the "St.-Louis" vs "St Louis" example in
+    // PipelineMatcher's own doc comment: SpaceSkipCandidate/
+    // SymbolSkipCandidate could only skip extra *input* separators, never
+    // advance past a dictionary-side one missing from the input entirely.
+    #[test]
+    fn pipeline_matches_missing_dictionary_side_space() {
+        let mut dict: SuffixTree<char, (bool, Symbol)> = SuffixTree::new();
+        dict.insert(" St Louis ".chars(), (false, Symbol(0)));
+
+        let mut pipeline = PipelineMatcher::new(&dict, true, true, false);
+        let found = " St.-Louis ".chars().enumerate()
+            .any(|(offset, ch)| !pipeline.feed(offset, ch).is_empty());
+
+        assert!(found, "\"St.-Louis\" should match whole-word entry \"St Louis\" under -i");
+    }
+
+    // Regression test for a bug where the root candidate `feed()` seeds at
+    // every offset survived unadvanced through `SpaceSkipCandidate` (which
+    // no-ops on whitespace) when fed the leading padding space `main()`
+    // always adds, then later completed with that stale `start` -- an
+    // underflow panic at `m.start - 1` in debug builds, silent corruption
+    // in release. Feeds through the same padded sequence `main()` uses,
+    // rather than a raw unpadded iterator, to actually exercise it.
+    #[test]
+    fn pipeline_fed_padded_input_reports_no_stale_root_matches() {
+        let mut dict: SuffixTree<char, (bool, Symbol)> = SuffixTree::new();
+        dict.insert("tolkien".chars(), (false, Symbol(0)));
+
+        let mut pipeline = PipelineMatcher::new(&dict, false, true, false);
+        let padded: Vec<char> = Some(' ').into_iter()
+            .chain("tolkien".chars())
+            .chain(Some(' ').into_iter())
+            .collect();
+
+        let matches: Vec<Match<(bool, Symbol)>> = padded.iter().enumerate()
+            .flat_map(|(offset, &ch)| pipeline.feed(offset, ch))
+            .collect();
+
+        assert_eq!(matches.len(), 1, "expected one match, got {}", matches.len());
+        assert!(matches[0].start > 0, "start should never be the padding offset, was {}", matches[0].start);
+        assert_eq!(matches[0].start, 1);
+    }
+
+    // Regression test for a bug where `expand_dict_only` let a freshly-
+    // seeded root candidate (`matched: 0`) walk a dictionary-side separator
+    // edge for free, with zero real input consumed, before the per-
+    // character fallback's `moved || cand.matched > 0` guard ever got a
+    // chance to reject it. Under `-w -i` this let "Xtolkien" report a
+    // `FuzzyWholeWord` match for "tolkien" despite no whitespace preceding
+    // it in the input at all, and let "tolkienY" produce a match whose
+    // `start` lands on the synthetic leading padding offset.
+    #[test]
+    fn pipeline_whole_word_does_not_skip_dictionary_boundary_for_free() {
+        let mut dict: SuffixTree<char, (bool, Symbol)> = SuffixTree::new();
+        dict.insert(" tolkien ".chars(), (false, Symbol(0)));
+
+        let mut pipeline = PipelineMatcher::new(&dict, true, true, false);
+        let padded: Vec<char> = Some(' ').into_iter()
+            .chain("Xtolkien".chars())
+            .chain(Some(' ').into_iter())
+            .collect();
+        let matches: Vec<Match<(bool, Symbol)>> = padded.iter().enumerate()
+            .flat_map(|(offset, &ch)| pipeline.feed(offset, ch))
+            .collect();
+        assert!(matches.is_empty(), "\"Xtolkien\" should not match whole-word entry \"tolkien\" with no preceding boundary, got {} matches", matches.len());
+
+        let mut pipeline = PipelineMatcher::new(&dict, true, true, false);
+        let padded: Vec<char> = Some(' ').into_iter()
+            .chain("tolkienY".chars())
+            .chain(Some(' ').into_iter())
+            .collect();
+        let matches: Vec<Match<(bool, Symbol)>> = padded.iter().enumerate()
+            .flat_map(|(offset, &ch)| pipeline.feed(offset, ch))
+            .collect();
+        assert!(matches.is_empty(), "\"tolkienY\" should not match whole-word entry \"tolkien\" with no following boundary, got {} matches", matches.len());
+    }
+}
 
 pub mod suffix_tree {
-    use collections::BTreeMap;
+    use std::mem;
 
+    /// A memory-compacted (Patricia-style) trie: any chain of single-child
+    /// nodes is collapsed into one `Edge` carrying the whole run of elements
+    /// as its label, rather than one node per element. Edges are split
+    /// lazily, on `insert`, the first time a new key diverges partway
+    /// through an existing label.
     pub struct SuffixTree<E, V> {
-        suffixes: BTreeMap<E, SuffixTree<E, V>>,
+        edges: Vec<Edge<E, V>>,
         pub value: Option<V>,
     }
 
-    impl<E: Ord + Clone, V> SuffixTree<E, V> {
+    struct Edge<E, V> {
+        label: Vec<E>,
+        target: SuffixTree<E, V>,
+    }
+
+    fn common_prefix_len<E: Eq>(a: &[E], b: &[E]) -> usize {
+        a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count()
+    }
+
+    impl<E: Eq + Clone, V> SuffixTree<E, V> {
         pub fn new() -> SuffixTree<E, V> {
             SuffixTree {
-                suffixes: BTreeMap::new(),
+                edges: Vec::new(),
                 value: None,
             }
         }
@@ -325,56 +1009,183 @@ pub mod suffix_tree {
             self.value.is_some()
         }
 
+        /// The outgoing edges of this node, as `(label, target)` pairs. A
+        /// label may carry more than one element when the run below it
+        /// has no other branches.
+        pub fn edges<'a>(&'a self) -> Box<Iterator<Item=(&'a [E], &'a SuffixTree<E, V>)> + 'a> {
+            Box::new(self.edges.iter().map(|e| (&e.label[..], &e.target)))
+        }
+
         pub fn insert<Iter: Iterator<Item=E>>(&mut self, el: Iter, value: V) {
-            unsafe {
-                let mut tree: *mut SuffixTree<E, V> = self;
-                for i in el {
-                    let new = match (*tree).suffixes.get_mut(&i) {
-                        Some(next) => next as *mut SuffixTree<E, V>,
-                        None => {
-                            (*tree).suffixes.insert(i.clone(), SuffixTree::new());
-                            (*tree).suffixes.get_mut(&i).unwrap() as *mut SuffixTree<E, V>
-                        }
-                    };
-                    tree = new;
+            self.insert_vec(el.collect(), value);
+        }
+
+        fn insert_vec(&mut self, key: Vec<E>, value: V) {
+            if key.is_empty() {
+                self.value = Some(value);
+                return;
+            }
+            match self.edges.iter().position(|e| e.label[0] == key[0]) {
+                Some(i) => {
+                    let common = common_prefix_len(&self.edges[i].label, &key);
+                    if common < self.edges[i].label.len() {
+                        self.edges[i].split(common);
+                    }
+                    let rest = key[common..].to_vec();
+                    self.edges[i].target.insert_vec(rest, value);
+                }
+                None => {
+                    let mut target = SuffixTree::new();
+                    target.value = Some(value);
+                    self.edges.push(Edge { label: key, target: target });
                 }
-                (*tree).value = Some(value);
             }
         }
     }
 
+    impl<E, V> Edge<E, V> {
+        /// Split this edge at `at`, inserting a fresh branch node so that
+        /// the first `at` elements of the label lead to it, and the
+        /// remaining elements (with whatever this edge used to point to)
+        /// become a new edge below it.
+        fn split(&mut self, at: usize) {
+            let tail_label = self.label.split_off(at);
+            let old_target = mem::replace(&mut self.target, SuffixTree { edges: Vec::new(), value: None });
+            self.target.edges.push(Edge { label: tail_label, target: old_target });
+        }
+    }
+
+    enum Position<'a, E: 'a, V: 'a> {
+        /// Sitting exactly on a node.
+        Node(&'a SuffixTree<E, V>),
+        /// Partway along an edge's label, having consumed `consumed`
+        /// elements of it so far.
+        OnEdge(&'a Edge<E, V>, usize),
+    }
+
+    // Implemented by hand rather than derived: Position only holds
+    // references and a `usize`, so it's `Copy` regardless of whether `E`
+    // and `V` are -- but `#[derive(Clone, Copy)]` would have added
+    // `E: Clone`/`V: Clone` bounds that don't reflect that.
+    impl<'a, E, V> Clone for Position<'a, E, V> {
+        fn clone(&self) -> Position<'a, E, V> { *self }
+    }
+
+    impl<'a, E, V> Copy for Position<'a, E, V> {}
+
     pub struct Cursor<'a, E: 'a, V: 'a> {
-        cursor: &'a SuffixTree<E, V>,
+        position: Position<'a, E, V>,
         pub path: Vec<E>,
     }
 
     impl<'a, E: Clone, V> Clone for Cursor<'a, E, V> {
         fn clone(&self) -> Cursor<'a, E, V> {
-            Cursor {cursor: self.cursor, path: self.path.clone()}
+            Cursor {position: self.position, path: self.path.clone()}
         }
     }
 
-    impl<'a, E: Ord, V> Cursor<'a, E, V> {
+    impl<'a, E: Eq + Clone, V> Cursor<'a, E, V> {
         pub fn new(array: &'a SuffixTree<E, V>) -> Cursor<'a, E, V> {
             Cursor {
-                cursor: array,
+                position: Position::Node(array),
                 path: Vec::new(),
             }
         }
 
         pub fn go(mut self, el: E) -> Option<Cursor<'a, E, V>> {
-            match self.cursor.suffixes.get(&el) {
-                Some(next) => {
-                    self.cursor = next;
-                    self.path.push(el);
-                    Some(self)
+            let next = match self.position {
+                Position::Node(node) => {
+                    match node.edges.iter().find(|e| e.label[0] == el) {
+                        Some(edge) => {
+                            if edge.label.len() == 1 {
+                                Position::Node(&edge.target)
+                            } else {
+                                Position::OnEdge(edge, 1)
+                            }
+                        }
+                        None => return None,
+                    }
                 }
-                None => None
-            }
+                Position::OnEdge(edge, consumed) => {
+                    if edge.label[consumed] == el {
+                        if consumed + 1 == edge.label.len() {
+                            Position::Node(&edge.target)
+                        } else {
+                            Position::OnEdge(edge, consumed + 1)
+                        }
+                    } else {
+                        return None;
+                    }
+                }
+            };
+            self.position = next;
+            self.path.push(el);
+            Some(self)
         }
 
+        /// Like `go`, but advances along whichever outgoing element
+        /// satisfies `pred` rather than requiring exact equality to a
+        /// supplied element -- e.g. for a case-insensitive step, or for
+        /// stepping past a dictionary-side separator that has no
+        /// corresponding input character to supply at all.
+        pub fn go_matching<F: Fn(&E) -> bool>(self, pred: F) -> Option<Cursor<'a, E, V>> {
+            let el = match self.position {
+                Position::Node(node) => node.edges.iter().find(|e| pred(&e.label[0])).map(|e| e.label[0].clone()),
+                Position::OnEdge(edge, consumed) => {
+                    if pred(&edge.label[consumed]) { Some(edge.label[consumed].clone()) } else { None }
+                }
+            };
+            let el = match el {
+                Some(el) => el,
+                None => return None,
+            };
+            self.go(el)
+        }
+
+        /// The node this cursor would reach by following the rest of its
+        /// current edge, if any. Note that while mid-edge this is the
+        /// edge's far end, not the cursor's actual position -- use
+        /// `is_terminal` rather than `get().is_terminal()` to ask whether
+        /// the cursor itself sits on a dictionary entry.
         pub fn get(&self) -> &'a SuffixTree<E, V> {
-            self.cursor
+            match self.position {
+                Position::Node(node) => node,
+                Position::OnEdge(edge, _) => &edge.target,
+            }
+        }
+
+        pub fn is_terminal(&self) -> bool {
+            match self.position {
+                Position::Node(node) => node.is_terminal(),
+                Position::OnEdge(_, _) => false,
+            }
+        }
+
+        /// The portion of the current edge's label this cursor has not yet
+        /// consumed -- empty when the cursor sits exactly on a node rather
+        /// than partway along an edge. Since a compacted edge has no
+        /// branches along its length, these elements are exactly what the
+        /// cursor is still guaranteed to require to reach `get()`, same as
+        /// anything `get()` itself requires beyond that.
+        pub fn remaining_label(&self) -> &'a [E] {
+            match self.position {
+                Position::Node(_) => &[],
+                Position::OnEdge(edge, consumed) => &edge.label[consumed..],
+            }
+        }
+
+        /// A key suitable for deduplicating cursors that denote the same
+        /// position in the trie: the node reached by the rest of the
+        /// current edge, paired with how many elements of that edge remain
+        /// unconsumed. Two cursors with equal `identity()` are at the same
+        /// point in the trie even if they got there along different
+        /// edges (impossible here, but keeps this robust to future
+        /// merging) or by different numbers of `go` calls.
+        pub fn identity(&self) -> (*const SuffixTree<E, V>, usize) {
+            match self.position {
+                Position::Node(node) => (node as *const SuffixTree<E, V>, 0),
+                Position::OnEdge(edge, consumed) => (&edge.target as *const SuffixTree<E, V>, edge.label.len() - consumed),
+            }
         }
     }
 }